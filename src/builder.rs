@@ -0,0 +1,409 @@
+//! A pre-bind socket configuration builder.
+//!
+//! `UdpSasBuilder` lets a caller configure socket options that have to be applied before (or
+//! at) bind time, and that [`bind_sas`][crate::UdpSas::bind_sas] alone cannot express, such as
+//! `SO_REUSEPORT` sharding across worker processes or dual-stack control via `IPV6_V6ONLY`.
+
+use std::io;
+use std::mem;
+use std::net::SocketAddr;
+
+use mio::net::UdpSocket;
+
+#[cfg(target_family = "unix")]
+use libc::AF_INET;
+#[cfg(target_family = "unix")]
+use libc::AF_INET6;
+
+#[cfg(target_family = "windows")]
+use winapi::shared::ws2def::AF_INET;
+#[cfg(target_family = "windows")]
+use winapi::shared::ws2def::AF_INET6;
+
+use udp_sas::{set_pktinfo, set_recv_ecn, set_udp_gro};
+
+/// Builder for a [`mio::net::UdpSocket`] that needs options applied before bind.
+///
+/// ```no_run
+/// use udp_sas_mio::UdpSasBuilder;
+///
+/// # fn main() -> std::io::Result<()> {
+/// let sock = UdpSasBuilder::new()
+///     .reuse_address(true)
+///     .reuse_port(true)
+///     .only_v6(false)
+///     .bind("0.0.0.0:30012".parse().unwrap())?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Copy, Debug, Default)]
+pub struct UdpSasBuilder {
+    reuse_address: bool,
+    reuse_port: bool,
+    only_v6: Option<bool>,
+    send_buffer_size: Option<usize>,
+    recv_buffer_size: Option<usize>,
+}
+
+impl UdpSasBuilder {
+    /// Creates a new builder with no options set.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets `SO_REUSEADDR`.
+    pub fn reuse_address(mut self, reuse: bool) -> Self {
+        self.reuse_address = reuse;
+        self
+    }
+
+    /// Sets `SO_REUSEPORT`, allowing several sockets to share the same port (e.g. one per
+    /// worker process, with the kernel load-balancing datagrams between them).
+    ///
+    /// Only supported on Linux, Android, FreeBSD, and macOS; [`bind`][UdpSasBuilder::bind]
+    /// returns an error on other platforms (including Windows) rather than silently ignoring
+    /// the option.
+    pub fn reuse_port(mut self, reuse: bool) -> Self {
+        self.reuse_port = reuse;
+        self
+    }
+
+    /// Sets `IPV6_V6ONLY`. Only meaningful when binding to an IPv6 address.
+    pub fn only_v6(mut self, only_v6: bool) -> Self {
+        self.only_v6 = Some(only_v6);
+        self
+    }
+
+    /// Sets `SO_SNDBUF`.
+    pub fn send_buffer_size(mut self, size: usize) -> Self {
+        self.send_buffer_size = Some(size);
+        self
+    }
+
+    /// Sets `SO_RCVBUF`.
+    pub fn recv_buffer_size(mut self, size: usize) -> Self {
+        self.recv_buffer_size = Some(size);
+        self
+    }
+
+    /// Creates the socket, applies the configured options, enables
+    /// `IP_PKTINFO`/`IPV6_RECVPKTINFO` and `IP_RECVTOS`/`IPV6_RECVTCLASS` (the same options
+    /// [`bind_sas`][crate::UdpSas::bind_sas] enables), binds to `addr`, and hands back a
+    /// ready-to-use [`mio::net::UdpSocket`].
+    pub fn bind(self, addr: SocketAddr) -> io::Result<UdpSocket> {
+        #[cfg(target_family = "unix")]
+        return unix::bind(self, addr);
+        #[cfg(target_family = "windows")]
+        return windows::bind(self, addr);
+    }
+}
+
+#[cfg(target_family = "unix")]
+mod unix {
+    use super::*;
+    use std::os::unix::io::FromRawFd;
+
+    pub(super) fn bind(opts: UdpSasBuilder, addr: SocketAddr) -> io::Result<UdpSocket> {
+        unsafe {
+            let family = if addr.is_ipv4() { AF_INET } else { AF_INET6 };
+            let fd = libc::socket(family, libc::SOCK_DGRAM, 0);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if let Err(e) = apply(fd, family, &opts, addr.is_ipv6()) {
+                libc::close(fd);
+                return Err(e);
+            }
+            let (raw_addr, addr_len) = raw_sockaddr(&addr);
+            if libc::bind(fd, &raw_addr as *const _ as *const libc::sockaddr, addr_len) < 0 {
+                let err = io::Error::last_os_error();
+                libc::close(fd);
+                return Err(err);
+            }
+            let std_sock = std::net::UdpSocket::from_raw_fd(fd);
+            std_sock.set_nonblocking(true)?;
+            let sock = UdpSocket::from_std(std_sock);
+            set_pktinfo(fd, family)?;
+            set_recv_ecn(fd, family)?;
+            // UDP_GRO support varies by kernel version; degrade gracefully when unavailable, as
+            // bind_sas does.
+            let _ = set_udp_gro(fd);
+            Ok(sock)
+        }
+    }
+
+    unsafe fn apply(
+        fd: libc::c_int,
+        family: libc::c_int,
+        opts: &UdpSasBuilder,
+        is_ipv6: bool,
+    ) -> io::Result<()> {
+        if opts.reuse_address {
+            setsockopt(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, 1)?;
+        }
+        #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd", target_os = "macos"))]
+        if opts.reuse_port {
+            setsockopt(fd, libc::SOL_SOCKET, libc::SO_REUSEPORT, 1)?;
+        }
+        #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd", target_os = "macos")))]
+        if opts.reuse_port {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "SO_REUSEPORT is not supported on this platform",
+            ));
+        }
+        if is_ipv6 {
+            if let Some(only_v6) = opts.only_v6 {
+                setsockopt(fd, libc::IPPROTO_IPV6, libc::IPV6_V6ONLY, only_v6 as libc::c_int)?;
+            }
+        }
+        let _ = family;
+        if let Some(size) = opts.send_buffer_size {
+            setsockopt(fd, libc::SOL_SOCKET, libc::SO_SNDBUF, size as libc::c_int)?;
+        }
+        if let Some(size) = opts.recv_buffer_size {
+            setsockopt(fd, libc::SOL_SOCKET, libc::SO_RCVBUF, size as libc::c_int)?;
+        }
+        Ok(())
+    }
+
+    unsafe fn setsockopt(
+        fd: libc::c_int,
+        level: libc::c_int,
+        name: libc::c_int,
+        value: libc::c_int,
+    ) -> io::Result<()> {
+        let ret = libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const _ as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        );
+        if ret < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn raw_sockaddr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+        let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let len = match addr {
+            SocketAddr::V4(addr) => {
+                let sin = &mut storage as *mut _ as *mut libc::sockaddr_in;
+                unsafe {
+                    (*sin).sin_family = AF_INET as libc::sa_family_t;
+                    (*sin).sin_port = addr.port().to_be();
+                    (*sin).sin_addr = libc::in_addr {
+                        s_addr: u32::from_ne_bytes(addr.ip().octets()),
+                    };
+                }
+                mem::size_of::<libc::sockaddr_in>() as libc::socklen_t
+            }
+            SocketAddr::V6(addr) => {
+                let sin6 = &mut storage as *mut _ as *mut libc::sockaddr_in6;
+                unsafe {
+                    (*sin6).sin6_family = AF_INET6 as libc::sa_family_t;
+                    (*sin6).sin6_port = addr.port().to_be();
+                    (*sin6).sin6_flowinfo = addr.flowinfo();
+                    (*sin6).sin6_addr = libc::in6_addr {
+                        s6_addr: addr.ip().octets(),
+                    };
+                    (*sin6).sin6_scope_id = addr.scope_id();
+                }
+                mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t
+            }
+        };
+        // `storage` itself is already a `sockaddr_storage` holding the bytes written above through
+        // the `sin`/`sin6` aliases; returning it directly avoids re-deriving a pointer to it (which
+        // previously read back uninitialized stack memory instead of the fields just set).
+        (storage, len)
+    }
+}
+
+#[cfg(target_family = "windows")]
+mod windows {
+    use super::*;
+    use std::convert::TryInto;
+    use std::mem;
+    use std::os::windows::io::FromRawSocket;
+
+    use winapi::shared::ws2def::{IPPROTO_UDP, SOCK_DGRAM, SOL_SOCKET, SO_REUSEADDR};
+    use winapi::shared::ws2ipdef::IPV6_V6ONLY;
+    use winapi::um::winsock2::{
+        bind as wsa_bind, closesocket, setsockopt, socket, SOCKET_ERROR, SO_RCVBUF, SO_SNDBUF,
+    };
+
+    const IPPROTO_IPV6: i32 = 41;
+
+    pub(super) fn bind(opts: UdpSasBuilder, addr: SocketAddr) -> io::Result<UdpSocket> {
+        unsafe {
+            let family = if addr.is_ipv4() { AF_INET } else { AF_INET6 };
+            let sock = socket(family, SOCK_DGRAM, IPPROTO_UDP as i32);
+            if sock == winapi::um::winsock2::INVALID_SOCKET {
+                return Err(io::Error::last_os_error());
+            }
+            if let Err(e) = apply(sock, &opts, addr.is_ipv6()) {
+                closesocket(sock);
+                return Err(e);
+            }
+            let (raw_addr, addr_len) = super::windows_raw_sockaddr(&addr);
+            if wsa_bind(sock, &raw_addr as *const _ as *const _, addr_len) == SOCKET_ERROR {
+                let err = io::Error::last_os_error();
+                closesocket(sock);
+                return Err(err);
+            }
+            let std_sock = std::net::UdpSocket::from_raw_socket(sock.try_into().unwrap());
+            std_sock.set_nonblocking(true)?;
+            let mio_sock = UdpSocket::from_std(std_sock);
+            set_pktinfo(sock.try_into().unwrap(), family)?;
+            set_recv_ecn(sock.try_into().unwrap(), family)?;
+            // UDP_GRO support varies by kernel version; degrade gracefully when unavailable, as
+            // bind_sas does.
+            let _ = set_udp_gro(sock.try_into().unwrap());
+            Ok(mio_sock)
+        }
+    }
+
+    unsafe fn apply(
+        sock: winapi::um::winsock2::SOCKET,
+        opts: &UdpSasBuilder,
+        is_ipv6: bool,
+    ) -> io::Result<()> {
+        if opts.reuse_address {
+            setopt(sock, SOL_SOCKET as i32, SO_REUSEADDR, 1)?;
+        }
+        if opts.reuse_port {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "SO_REUSEPORT is not supported on Windows",
+            ));
+        }
+        if is_ipv6 {
+            if let Some(only_v6) = opts.only_v6 {
+                setopt(sock, IPPROTO_IPV6, IPV6_V6ONLY, only_v6 as i32)?;
+            }
+        }
+        if let Some(size) = opts.send_buffer_size {
+            setopt(sock, SOL_SOCKET as i32, SO_SNDBUF, size as i32)?;
+        }
+        if let Some(size) = opts.recv_buffer_size {
+            setopt(sock, SOL_SOCKET as i32, SO_RCVBUF, size as i32)?;
+        }
+        Ok(())
+    }
+
+    unsafe fn setopt(
+        sock: winapi::um::winsock2::SOCKET,
+        level: i32,
+        name: i32,
+        value: i32,
+    ) -> io::Result<()> {
+        let ret = setsockopt(
+            sock,
+            level,
+            name,
+            &value as *const _ as *const i8,
+            mem::size_of::<i32>() as i32,
+        );
+        if ret == SOCKET_ERROR {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(target_family = "windows")]
+fn windows_raw_sockaddr(
+    addr: &SocketAddr,
+) -> (winapi::shared::ws2def::SOCKADDR_STORAGE, i32) {
+    use std::mem;
+    use winapi::shared::ws2def::{AF_INET, AF_INET6, SOCKADDR_IN};
+    use winapi::shared::ws2ipdef::SOCKADDR_IN6_LH;
+
+    unsafe {
+        let mut storage: winapi::shared::ws2def::SOCKADDR_STORAGE = mem::zeroed();
+        match addr {
+            SocketAddr::V4(addr) => {
+                let sa = &mut storage as *mut _ as *mut SOCKADDR_IN;
+                (*sa).sin_family = AF_INET as u16;
+                (*sa).sin_port = addr.port().to_be();
+                *(*sa).sin_addr.S_un.S_addr_mut() = u32::from_ne_bytes(addr.ip().octets());
+                (storage, mem::size_of::<SOCKADDR_IN>() as i32)
+            }
+            SocketAddr::V6(addr) => {
+                let sa = &mut storage as *mut _ as *mut SOCKADDR_IN6_LH;
+                (*sa).sin6_family = AF_INET6 as u16;
+                (*sa).sin6_port = addr.port().to_be();
+                (*sa).sin6_flowinfo = addr.flowinfo();
+                *(*sa).sin6_addr.u.Byte_mut() = addr.ip().octets();
+                *(*sa).u.sin6_scope_id_mut() = addr.scope_id();
+                (storage, mem::size_of::<SOCKADDR_IN6_LH>() as i32)
+            }
+        }
+    }
+}
+
+#[cfg(all(test, target_family = "unix"))]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use crate::UdpSas;
+
+    // Retries recv_sas since `bind()` hands back a non-blocking socket.
+    fn recv_sas_blocking(sock: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, std::net::IpAddr)> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            match sock.recv_sas(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock && Instant::now() < deadline => {
+                    thread::sleep(Duration::from_millis(10));
+                }
+                result => return result,
+            }
+        }
+    }
+
+    #[test]
+    fn bind_actually_binds_to_requested_address() {
+        let srv = UdpSasBuilder::new()
+            .reuse_address(true)
+            .bind("127.0.0.1:0".parse().unwrap())
+            .unwrap();
+        let srv_addr = srv.local_addr().unwrap();
+        // A garbage sockaddr would either fail to bind, or bind to an address other than the
+        // loopback address/port we asked for.
+        assert_eq!(srv_addr.ip(), "127.0.0.1".parse::<std::net::IpAddr>().unwrap());
+        assert_ne!(srv_addr.port(), 0);
+
+        let cli = UdpSasBuilder::new().bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let cli_addr = cli.local_addr().unwrap();
+
+        cli.send_sas(b"ping", &srv_addr, &cli_addr.ip()).unwrap();
+        let mut buf = [0u8; 16];
+        let (nb, peer, local) = recv_sas_blocking(&srv, &mut buf).unwrap();
+        assert_eq!(&buf[..nb], b"ping");
+        assert_eq!(peer, cli_addr);
+        assert_eq!(local, srv_addr.ip());
+    }
+
+    #[test]
+    fn reuse_port_allows_sharing_the_same_port() {
+        let first = UdpSasBuilder::new()
+            .reuse_address(true)
+            .reuse_port(true)
+            .bind("127.0.0.1:0".parse().unwrap())
+            .unwrap();
+        let addr = first.local_addr().unwrap();
+
+        let second = UdpSasBuilder::new()
+            .reuse_address(true)
+            .reuse_port(true)
+            .bind(addr)
+            .unwrap();
+        assert_eq!(second.local_addr().unwrap(), addr);
+    }
+}
@@ -3,10 +3,13 @@
 //! multiple network interfaces.
 //!
 //! The implementation relies on socket options [`IP_PKTINFO`] \(for IPv4) and [`IPV6_RECVPKTINFO`]
-//! \(for IPv6).
+//! \(for IPv6). On Unix this rides on `sendmsg(2)`/`recvmsg(2)` ancillary data; on Windows it rides
+//! on the `WSASendMsg`/`WSARecvMsg` extension functions, resolved once via `WSAIoctl` in the
+//! [`udp_sas`] dependency, so the two platforms behave identically from this crate's point of view.
 //!
 //! [extension trait]:      trait.UdpSas.html
-//! [`IP_PKTINFO`]:         http://man7.org/linux/man-pages/man7/ip.7.html      
+//! [`udp_sas`]:            https://crates.io/crates/udp_sas
+//! [`IP_PKTINFO`]:         http://man7.org/linux/man-pages/man7/ip.7.html
 //! [`IPV6_RECVPKTINFO`]:   http://man7.org/linux/man-pages/man7/ipv6.7.html
 //!
 //!
@@ -140,7 +143,40 @@ use std::convert::TryInto;
 
 use mio::net::UdpSocket;
 
-use udp_sas::{recv_sas, send_sas, set_pktinfo};
+mod builder;
+pub use builder::UdpSasBuilder;
+
+pub use udp_sas::Ecn;
+use udp_sas::{
+    gso_supported, recv_sas, recv_sas_batch, recv_sas_ecn, recv_sas_full, recv_sas_gro, send_sas,
+    send_sas_batch, send_sas_ecn, send_sas_if, send_sas_segmented, set_pktinfo, set_recv_ecn,
+    set_udp_gro,
+};
+
+use std::sync::atomic::{AtomicU8, Ordering};
+
+const GSO_UNKNOWN: u8 = 0;
+const GSO_SUPPORTED: u8 = 1;
+const GSO_UNSUPPORTED: u8 = 2;
+
+static GSO_CACHE: AtomicU8 = AtomicU8::new(GSO_UNKNOWN);
+
+/// Returns whether `UDP_SEGMENT`/`UDP_GRO` are usable on this kernel, caching the result of the
+/// first check.
+fn gso_available() -> bool {
+    match GSO_CACHE.load(Ordering::Relaxed) {
+        GSO_SUPPORTED => true,
+        GSO_UNSUPPORTED => false,
+        _ => {
+            let supported = gso_supported();
+            GSO_CACHE.store(
+                if supported { GSO_SUPPORTED } else { GSO_UNSUPPORTED },
+                Ordering::Relaxed,
+            );
+            supported
+        }
+    }
+}
 
 /// An extension trait to support source address selection in `mio::net::UdpSocket`
 ///
@@ -168,6 +204,103 @@ pub trait UdpSas: Sized {
     /// source socket address (peer address), and the destination ip address (local address).
     ///
     fn recv_sas(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, IpAddr)>;
+
+    /// Sends several datagrams in a single call, each with its own `target` and `local` source
+    /// address.
+    ///
+    /// On platforms that support it (Linux, via [`sendmmsg(2)`]) this issues a single syscall for
+    /// the whole batch; elsewhere it falls back to calling [`send_sas`][UdpSas::send_sas] once per
+    /// message. On success, returns the number of messages actually sent.
+    ///
+    /// [`sendmmsg(2)`]: http://man7.org/linux/man-pages/man2/sendmmsg.2.html
+    ///
+    fn send_sas_batch(&self, msgs: &[(&[u8], SocketAddr, IpAddr)]) -> io::Result<usize>;
+
+    /// Receives several datagrams in a single call.
+    ///
+    /// `bufs` holds one receive buffer per datagram; `out` is filled, one entry per datagram
+    /// actually received, with the same `(nb, source, local)` tuple that [`recv_sas`] returns.
+    /// Entries beyond the number of messages actually received are left untouched. On platforms
+    /// that support it (Linux, via [`recvmmsg(2)`]) this issues a single syscall for the whole
+    /// batch; elsewhere it falls back to calling [`recv_sas`][UdpSas::recv_sas] once per message.
+    /// On success, returns the number of messages actually received.
+    ///
+    /// [`recv_sas`]: UdpSas::recv_sas
+    /// [`recvmmsg(2)`]: http://man7.org/linux/man-pages/man2/recvmmsg.2.html
+    ///
+    fn recv_sas_batch(
+        &self,
+        bufs: &mut [&mut [u8]],
+        out: &mut [(usize, SocketAddr, IpAddr)],
+    ) -> io::Result<usize>;
+
+    /// Sends a datagram to the given `target` address, selecting the outgoing interface by
+    /// index rather than by source address.
+    ///
+    /// This leaves the kernel free to pick the source address for `ifindex`, which is useful
+    /// when several interfaces share the same address and only the interface itself
+    /// disambiguates the reply path. See [`send_sas`][UdpSas::send_sas] to select by source
+    /// address instead.
+    ///
+    /// On success, returns the number of bytes written.
+    ///
+    fn send_sas_if(&self, buf: &[u8], target: &SocketAddr, ifindex: u32) -> io::Result<usize>;
+
+    /// Receive a datagram, like [`recv_sas`][UdpSas::recv_sas], but also return the index of the
+    /// interface the datagram arrived on.
+    ///
+    /// On success, returns a tuple `(nb, source, local, ifindex)`. The `ipi_ifindex`/
+    /// `ipi6_ifindex` field rides in the same `IP_PKTINFO`/`IPV6_PKTINFO` cmsg as `local`, so it
+    /// is present whenever `local` is (and this function already errors when `local` is
+    /// missing); `ifindex` is `0` only in the defensive case where the kernel reports that cmsg
+    /// without an interface index, which does not happen in practice. `0` is not a valid
+    /// interface index, so callers can treat it as "unknown" without risk of colliding with a
+    /// real interface.
+    ///
+    fn recv_sas_full(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, IpAddr, u32)>;
+
+    /// Sends a datagram like [`send_sas`][UdpSas::send_sas], marking it with the given ECN
+    /// codepoint (e.g. `Ecn::Ect0`) via an `IP_TOS`/`IPV6_TCLASS` control message.
+    ///
+    /// On success, returns the number of bytes written.
+    ///
+    fn send_sas_ecn(
+        &self,
+        buf: &[u8],
+        target: &SocketAddr,
+        local: &IpAddr,
+        ecn: Ecn,
+    ) -> io::Result<usize>;
+
+    /// Receive a datagram, like [`recv_sas`][UdpSas::recv_sas], but also return the ECN
+    /// codepoint the datagram was marked with.
+    ///
+    /// On success, returns a tuple `(nb, source, local, ecn)`.
+    ///
+    fn recv_sas_ecn(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, IpAddr, Ecn)>;
+
+    /// Sends `buf` as a run of `segment_size`-byte datagrams to `target` using UDP
+    /// segmentation offload (`UDP_SEGMENT`), so the kernel slices `buf` into wire datagrams in
+    /// a single syscall.
+    ///
+    /// Falls back to one [`send_sas`][UdpSas::send_sas] call per segment on kernels that don't
+    /// support `UDP_SEGMENT`. On success, returns the number of bytes written.
+    ///
+    fn send_sas_segmented(
+        &self,
+        buf: &[u8],
+        target: &SocketAddr,
+        local: &IpAddr,
+        segment_size: u16,
+    ) -> io::Result<usize>;
+
+    /// Receive a datagram, like [`recv_sas`][UdpSas::recv_sas], but also return the GRO segment
+    /// size if the kernel coalesced several wire datagrams into `buf` (`None` otherwise, in
+    /// which case `buf` holds a single datagram as usual).
+    ///
+    /// On success, returns a tuple `(nb, source, local, segment_size)`.
+    ///
+    fn recv_sas_gro(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, IpAddr, Option<u16>)>;
 }
 
 impl UdpSas for UdpSocket {
@@ -179,9 +312,13 @@ impl UdpSas for UdpSocket {
             AF_INET6
         };
         #[cfg(target_family = "unix")]
-        set_pktinfo(sock.as_raw_fd(), family)?;
+        let fd = sock.as_raw_fd();
         #[cfg(target_family = "windows")]
-        set_pktinfo(sock.as_raw_socket().try_into().unwrap(), family)?;
+        let fd = sock.as_raw_socket().try_into().unwrap();
+        set_pktinfo(fd, family)?;
+        set_recv_ecn(fd, family)?;
+        // UDP_GRO support varies by kernel version; degrade gracefully when unavailable.
+        let _ = set_udp_gro(fd);
         Ok(sock)
     }
 
@@ -203,11 +340,246 @@ impl UdpSas for UdpSocket {
             (Some(src), Some(local)) => Ok((nb, src, local)),
             (None, _) => Err(io::Error::new(
                     io::ErrorKind::InvalidData,
-                    "local address not available (IP_PKTINFO/IPV6_RECVPKTINFO may not be enabled on the socket)")),
+                    "source address not available (maybe the socket is connected)")),
             (_, None) => Err(io::Error::new(
                     io::ErrorKind::InvalidData,
-                    "source address not available (maybe the socket is connected)"
+                    "local address not available (IP_PKTINFO/IPV6_RECVPKTINFO may not be enabled on the socket)"
                     )),
         }
     }
+
+    fn send_sas_batch(&self, msgs: &[(&[u8], SocketAddr, IpAddr)]) -> io::Result<usize> {
+        #[cfg(target_family = "unix")]
+        let fd = self.as_raw_fd();
+        #[cfg(target_family = "windows")]
+        let fd = self.as_raw_socket();
+        send_sas_batch(fd, msgs)
+    }
+
+    fn recv_sas_batch(
+        &self,
+        bufs: &mut [&mut [u8]],
+        out: &mut [(usize, SocketAddr, IpAddr)],
+    ) -> io::Result<usize> {
+        #[cfg(target_family = "unix")]
+        let fd = self.as_raw_fd();
+        #[cfg(target_family = "windows")]
+        let fd = self.as_raw_socket();
+        recv_sas_batch(fd, bufs, out)
+    }
+
+    fn send_sas_if(&self, buf: &[u8], target: &SocketAddr, ifindex: u32) -> io::Result<usize> {
+        #[cfg(target_family = "unix")]
+        let fd = self.as_raw_fd();
+        #[cfg(target_family = "windows")]
+        let fd = self.as_raw_socket();
+        send_sas_if(fd, buf, target, ifindex)
+    }
+
+    fn recv_sas_full(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, IpAddr, u32)> {
+        #[cfg(target_family = "unix")]
+        let fd = self.as_raw_fd();
+        #[cfg(target_family = "windows")]
+        let fd = self.as_raw_socket();
+        let (nb, src, local, ifindex) = recv_sas_full(fd, buf)?;
+        match (src, local) {
+            (Some(src), Some(local)) => Ok((nb, src, local, ifindex.unwrap_or(0))),
+            (None, _) => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "source address not available (maybe the socket is connected)")),
+            (_, None) => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "local address not available (IP_PKTINFO/IPV6_RECVPKTINFO may not be enabled on the socket)"
+                    )),
+        }
+    }
+
+    fn send_sas_ecn(
+        &self,
+        buf: &[u8],
+        target: &SocketAddr,
+        local: &IpAddr,
+        ecn: Ecn,
+    ) -> io::Result<usize> {
+        #[cfg(target_family = "unix")]
+        let fd = self.as_raw_fd();
+        #[cfg(target_family = "windows")]
+        let fd = self.as_raw_socket();
+        send_sas_ecn(fd, buf, target, local, ecn)
+    }
+
+    fn recv_sas_ecn(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, IpAddr, Ecn)> {
+        #[cfg(target_family = "unix")]
+        let fd = self.as_raw_fd();
+        #[cfg(target_family = "windows")]
+        let fd = self.as_raw_socket();
+        let (nb, src, local, ecn) = recv_sas_ecn(fd, buf)?;
+        match (src, local) {
+            (Some(src), Some(local)) => Ok((nb, src, local, ecn)),
+            (None, _) => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "source address not available (maybe the socket is connected)")),
+            (_, None) => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "local address not available (IP_PKTINFO/IPV6_RECVPKTINFO may not be enabled on the socket)"
+                    )),
+        }
+    }
+
+    fn send_sas_segmented(
+        &self,
+        buf: &[u8],
+        target: &SocketAddr,
+        local: &IpAddr,
+        segment_size: u16,
+    ) -> io::Result<usize> {
+        if segment_size == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "segment_size must be non-zero",
+            ));
+        }
+        #[cfg(target_family = "unix")]
+        let fd = self.as_raw_fd();
+        #[cfg(target_family = "windows")]
+        let fd = self.as_raw_socket();
+        if gso_available() {
+            send_sas_segmented(fd, buf, target, local, segment_size)
+        } else {
+            let mut sent = 0;
+            for chunk in buf.chunks(segment_size as usize) {
+                sent += send_sas(fd, chunk, Some(target), Some(local))?;
+            }
+            Ok(sent)
+        }
+    }
+
+    fn recv_sas_gro(
+        &self,
+        buf: &mut [u8],
+    ) -> io::Result<(usize, SocketAddr, IpAddr, Option<u16>)> {
+        #[cfg(target_family = "unix")]
+        let fd = self.as_raw_fd();
+        #[cfg(target_family = "windows")]
+        let fd = self.as_raw_socket();
+        let (nb, src, local, segment_size) = recv_sas_gro(fd, buf)?;
+        match (src, local) {
+            (Some(src), Some(local)) => Ok((nb, src, local, segment_size)),
+            (None, _) => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "source address not available (maybe the socket is connected)")),
+            (_, None) => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "local address not available (IP_PKTINFO/IPV6_RECVPKTINFO may not be enabled on the socket)"
+                    )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    // recv_sas/recv_sas_full/etc. operate on a non-blocking mio socket, so poll briefly rather
+    // than assuming the datagram is already there.
+    fn retry_until_ready<T>(mut f: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            match f() {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock && Instant::now() < deadline => {
+                    thread::sleep(Duration::from_millis(10));
+                }
+                result => return result,
+            }
+        }
+    }
+
+    #[test]
+    fn send_sas_batch_and_recv_sas_batch_roundtrip() {
+        let srv = UdpSocket::bind_sas("127.0.0.1:0".parse().unwrap()).unwrap();
+        let srv_addr = srv.local_addr().unwrap();
+        let cli = UdpSocket::bind_sas("127.0.0.1:0".parse().unwrap()).unwrap();
+        let cli_addr = cli.local_addr().unwrap();
+
+        let msgs: [(&[u8], SocketAddr, IpAddr); 2] = [
+            (b"first", srv_addr, cli_addr.ip()),
+            (b"second", srv_addr, cli_addr.ip()),
+        ];
+        let sent = cli.send_sas_batch(&msgs).unwrap();
+        assert_eq!(sent, msgs.len());
+
+        let mut buf1 = [0u8; 16];
+        let mut buf2 = [0u8; 16];
+        let mut bufs: [&mut [u8]; 2] = [&mut buf1, &mut buf2];
+        let mut out = [(0usize, srv_addr, srv_addr.ip()); 2];
+        let received = retry_until_ready(|| srv.recv_sas_batch(&mut bufs, &mut out)).unwrap();
+        assert_eq!(received, msgs.len());
+        assert_eq!(&bufs[0][..out[0].0], b"first");
+        assert_eq!(&bufs[1][..out[1].0], b"second");
+        for (_, peer, local) in &out {
+            assert_eq!(*peer, cli_addr);
+            assert_eq!(*local, srv_addr.ip());
+        }
+    }
+
+    #[test]
+    fn send_sas_ecn_and_recv_sas_ecn_roundtrip() {
+        let srv = UdpSocket::bind_sas("127.0.0.1:0".parse().unwrap()).unwrap();
+        let srv_addr = srv.local_addr().unwrap();
+        let cli = UdpSocket::bind_sas("127.0.0.1:0".parse().unwrap()).unwrap();
+        let cli_addr = cli.local_addr().unwrap();
+
+        cli.send_sas_ecn(b"ping", &srv_addr, &cli_addr.ip(), Ecn::Ect0)
+            .unwrap();
+
+        let mut buf = [0u8; 16];
+        let (nb, peer, local, ecn) = retry_until_ready(|| srv.recv_sas_ecn(&mut buf)).unwrap();
+        assert_eq!(&buf[..nb], b"ping");
+        assert_eq!(peer, cli_addr);
+        assert_eq!(local, srv_addr.ip());
+        assert_eq!(ecn, Ecn::Ect0);
+    }
+
+    #[test]
+    fn send_sas_if_and_recv_sas_full_roundtrip() {
+        let srv = UdpSocket::bind_sas("127.0.0.1:0".parse().unwrap()).unwrap();
+        let srv_addr = srv.local_addr().unwrap();
+        let cli = UdpSocket::bind_sas("127.0.0.1:0".parse().unwrap()).unwrap();
+        let cli_addr = cli.local_addr().unwrap();
+
+        // ifindex 0 lets the kernel pick the outgoing interface, like `send_sas` with no
+        // explicit source address would.
+        cli.send_sas_if(b"ping", &srv_addr, 0).unwrap();
+
+        let mut buf = [0u8; 16];
+        let (nb, peer, local, ifindex) =
+            retry_until_ready(|| srv.recv_sas_full(&mut buf)).unwrap();
+        assert_eq!(&buf[..nb], b"ping");
+        assert_eq!(peer, cli_addr);
+        assert_eq!(local, srv_addr.ip());
+        assert!(ifindex > 0, "loopback should report a real interface index");
+    }
+
+    #[test]
+    fn send_sas_segmented_rejects_zero_segment_size() {
+        let sock = UdpSocket::bind_sas("127.0.0.1:0".parse().unwrap()).unwrap();
+        let target = sock.local_addr().unwrap();
+        let local = target.ip();
+        let err = sock
+            .send_sas_segmented(b"hello world", &target, &local, 0)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn send_sas_segmented_sends_the_whole_buffer() {
+        let sock = UdpSocket::bind_sas("127.0.0.1:0".parse().unwrap()).unwrap();
+        let target = sock.local_addr().unwrap();
+        let local = target.ip();
+        let buf = b"0123456789abcdef";
+        let nb = sock.send_sas_segmented(buf, &target, &local, 4).unwrap();
+        assert_eq!(nb, buf.len());
+    }
 }